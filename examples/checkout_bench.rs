@@ -0,0 +1,48 @@
+use std::time::{Duration, Instant};
+
+const TASKS: usize = 64;
+const ITERATIONS: usize = 1000;
+// The same size and max_concurrency in both modes, so that every locked
+// handler has a client of its own and the modes differ only by the lock
+const SIZE: usize = 16;
+
+async fn bench(stateless: bool) -> surf_pool::Result<Duration> {
+    let pool = surf_pool::SurfPoolBuilder::new(SIZE)?
+        .max_concurrency(SIZE)?
+        .stateless(stateless)
+        .build()
+        .await;
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..TASKS)
+        .map(|_| {
+            let pool = pool.clone();
+            async_std::task::spawn(async move {
+                for _ in 0..ITERATIONS {
+                    let handler = pool.get_handler().await;
+                    async_std::task::yield_now().await;
+                    drop(handler);
+                }
+            })
+        })
+        .collect();
+    for t in tasks {
+        t.await;
+    }
+    Ok(start.elapsed())
+}
+
+#[async_std::main]
+async fn main() -> surf_pool::Result<()> {
+    let checkouts = (TASKS * ITERATIONS) as f64;
+    for (name, stateless) in [("locked", false), ("stateless", true)] {
+        let elapsed = bench(stateless).await?;
+        println!(
+            "{:>9}: {} checkouts in {:?} ({:.0} checkouts/s)",
+            name,
+            checkouts,
+            elapsed,
+            checkouts / elapsed.as_secs_f64()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,249 @@
+//! Per-host politeness limits
+use crate::{Result, SurfPoolError};
+use async_weighted_semaphore::{Semaphore, SemaphoreGuardArc};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use surf::Url;
+
+/// The limits applied to every request towards a single host
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HostLimits {
+    max_concurrent: usize,
+    min_interval: Duration,
+}
+
+impl HostLimits {
+    /// This function is used to create a new set of limits
+    /// The parameter max_concurrent is the number of requests that can be in
+    /// flight towards the same host at the same time; it cannot be 0
+    /// The parameter min_interval is the minimum time between the start of two
+    /// consecutive requests towards the same host; a zero interval disables
+    /// the rate limit
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    /// use surf_pool::HostLimits;
+    ///
+    /// HostLimits::new(2, Duration::from_millis(500)).unwrap();
+    /// ```
+    pub fn new(max_concurrent: usize, min_interval: Duration) -> Result<Self> {
+        if max_concurrent == 0 {
+            return Err(SurfPoolError::HostLimitNotValid(max_concurrent));
+        }
+        Ok(HostLimits {
+            max_concurrent,
+            min_interval,
+        })
+    }
+    pub fn get_max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+    pub fn get_min_interval(&self) -> Duration {
+        self.min_interval
+    }
+}
+
+#[derive(Debug)]
+struct HostState {
+    limits: HostLimits,
+    semaphore: Arc<Semaphore>,
+    starting: async_std::sync::Mutex<()>,
+    next_start: Mutex<Option<Instant>>,
+}
+
+/// The permit held while a request towards a limited host is in flight
+#[derive(Debug)]
+pub(crate) struct HostPermit {
+    _sg: SemaphoreGuardArc,
+    state: Arc<HostState>,
+}
+
+impl HostPermit {
+    /// Acquire a resource of the pool, via `acquire`, once the request can be
+    /// started according to the min_interval, and consume the interval
+    /// The resource is not held while waiting for the interval, so a client
+    /// of the pool is never held idle by a rate limited host
+    /// The requests towards the same host start one at a time: the interval
+    /// cannot be consumed by another request while the resource is acquired,
+    /// hence the resource is never acquired in vain
+    pub(crate) async fn start_with<T, F, Fut>(&self, acquire: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let min_interval = self.state.limits.min_interval;
+        if min_interval == Duration::from_secs(0) {
+            return acquire().await;
+        }
+        let _starting = self.state.starting.lock().await;
+        let wait = self.state.pending(Instant::now());
+        if wait > Duration::from_secs(0) {
+            async_std::task::sleep(wait).await;
+        }
+        let resource = acquire().await;
+        *self.state.next_start.lock().unwrap() = Some(Instant::now() + min_interval);
+        resource
+    }
+}
+
+impl HostState {
+    fn new(limits: HostLimits) -> Self {
+        HostState {
+            limits,
+            semaphore: Arc::new(Semaphore::new(limits.max_concurrent)),
+            starting: async_std::sync::Mutex::new(()),
+            next_start: Mutex::new(None),
+        }
+    }
+
+    /// The time to wait before the next request can be started
+    fn pending(&self, now: Instant) -> Duration {
+        match *self.next_start.lock().unwrap() {
+            Some(at) if at > now => at - now,
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    /// A state is idle if nobody is using or waiting for it, and the next
+    /// request could start immediately: dropping it doesn't change the limits
+    fn is_idle(self: &Arc<Self>, now: Instant) -> bool {
+        Arc::strong_count(self) == 1 && self.pending(now) == Duration::from_secs(0)
+    }
+}
+
+/// The politeness policy shared by all the clients of a pool
+#[derive(Debug, Default)]
+pub(crate) struct HostPolicy {
+    default: Option<HostLimits>,
+    overrides: HashMap<String, HostLimits>,
+    states: std::sync::Mutex<HashMap<String, Arc<HostState>>>,
+}
+
+impl HostPolicy {
+    pub(crate) fn new(default: Option<HostLimits>, overrides: HashMap<String, HostLimits>) -> Self {
+        HostPolicy {
+            default,
+            overrides,
+            ..Default::default()
+        }
+    }
+
+    fn limits_for(&self, host: &str) -> Option<HostLimits> {
+        self.overrides.get(host).copied().or(self.default)
+    }
+
+    /// Wait until a request towards the host of the url is allowed by the
+    /// host concurrency; [`HostPermit::start_with`] enforces the min_interval
+    /// If the host is not limited, `None` is returned immediately
+    pub(crate) async fn acquire(&self, url: &Url) -> Option<HostPermit> {
+        let host = url.host_str()?.to_ascii_lowercase();
+        let limits = self.limits_for(&host)?;
+        let state = {
+            let mut states = self.states.lock().unwrap();
+            if !states.contains_key(&host) {
+                // Evict the idle hosts, to not grow without bounds when
+                // contacting many different hosts
+                let now = Instant::now();
+                states.retain(|_, state| !state.is_idle(now));
+            }
+            states
+                .entry(host)
+                .or_insert_with(|| Arc::new(HostState::new(limits)))
+                .clone()
+        };
+        let sg = state.semaphore.acquire_arc(1).await.unwrap();
+        Some(HostPermit { _sg: sg, state })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future::timeout;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn host_limits_not_valid() {
+        assert!(HostLimits::new(0, Duration::from_secs(1)).is_err());
+    }
+
+    #[async_std::test]
+    async fn concurrency_per_host() {
+        let limits = HostLimits::new(1, Duration::from_secs(0)).unwrap();
+        let uut = HostPolicy::new(Some(limits), HashMap::new());
+        let p1 = uut.acquire(&url("https://example.com/a")).await;
+        assert!(p1.is_some());
+        let blocked = timeout(
+            Duration::from_millis(50),
+            uut.acquire(&url("https://EXAMPLE.com/b")),
+        )
+        .await;
+        assert!(blocked.is_err());
+        let other = timeout(
+            Duration::from_millis(50),
+            uut.acquire(&url("https://example.org/")),
+        )
+        .await;
+        assert!(other.is_ok());
+        drop(p1);
+        let p2 = timeout(
+            Duration::from_millis(50),
+            uut.acquire(&url("https://example.com/b")),
+        )
+        .await;
+        assert!(p2.is_ok());
+    }
+
+    #[async_std::test]
+    async fn rate_per_host() {
+        let limits = HostLimits::new(10, Duration::from_millis(50)).unwrap();
+        let uut = HostPolicy::new(Some(limits), HashMap::new());
+        let start = Instant::now();
+        for _ in 0..3 {
+            let permit = uut.acquire(&url("https://example.com/")).await.unwrap();
+            permit.start_with(|| async {}).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[async_std::test]
+    async fn evict_idle_hosts() {
+        let limits = HostLimits::new(1, Duration::from_millis(50)).unwrap();
+        let uut = HostPolicy::new(Some(limits), HashMap::new());
+        let busy = uut.acquire(&url("https://busy.com/")).await.unwrap();
+        let recent = uut.acquire(&url("https://recent.com/")).await.unwrap();
+        recent.start_with(|| async {}).await;
+        drop(recent);
+        for i in 0..10 {
+            uut.acquire(&url(&format!("https://host{}.com/", i))).await;
+        }
+        // Only the last host, the busy one and the one with a pending
+        // interval are kept
+        assert_eq!(uut.states.lock().unwrap().len(), 3);
+        drop(busy);
+        async_std::task::sleep(Duration::from_millis(60)).await;
+        uut.acquire(&url("https://example.com/")).await;
+        assert_eq!(uut.states.lock().unwrap().len(), 1);
+    }
+
+    #[async_std::test]
+    async fn override_only() {
+        let limits = HostLimits::new(1, Duration::from_secs(0)).unwrap();
+        let mut overrides = HashMap::new();
+        overrides.insert("example.com".to_string(), limits);
+        let uut = HostPolicy::new(None, overrides);
+        assert!(uut.acquire(&url("https://example.org/")).await.is_none());
+        let _p = uut.acquire(&url("https://example.com/")).await.unwrap();
+        let blocked = timeout(
+            Duration::from_millis(50),
+            uut.acquire(&url("https://example.com/")),
+        )
+        .await;
+        assert!(blocked.is_err());
+    }
+}
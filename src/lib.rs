@@ -1,11 +1,33 @@
 //! Connection pool for Surf
 use async_std::sync::{Mutex, MutexGuardArc};
 use async_weighted_semaphore::{Semaphore, SemaphoreGuardArc};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use surf::Client;
 use thiserror::Error;
 
+mod host;
+mod middleware;
+mod sizing;
+mod stats;
+mod template;
+mod throttle;
+
+pub use host::HostLimits;
+use host::{HostPermit, HostPolicy};
+pub use middleware::PoolMiddleware;
+pub use sizing::SizingReport;
+use sizing::{Permit, Usage};
+use stats::SlotState;
+pub use stats::{ErrorKind, LastError, MiddlewareStats, PoolStats, SlotStats};
+pub use template::TemplateStats;
+use template::Templates;
+use throttle::{MeteredReader, ResponseReader, TokenBucket};
+
 const MAX_POOL_SIZE: usize = 100;
+const MAX_CONCURRENCY: usize = 1000;
 /// Convenient Result redefinition that uses [SurfPoolError] as Error
 pub type Result<T> = ::std::result::Result<T, SurfPoolError>;
 
@@ -13,22 +35,58 @@ pub type Result<T> = ::std::result::Result<T, SurfPoolError>;
 /// The main struct, used to get a valid connection
 pub struct SurfPool {
     pool: Vec<Arc<Mutex<Client>>>,
+    clients: Vec<Client>,
+    slots: Vec<Arc<SlotState>>,
+    max_concurrency: usize,
+    next_shared: Arc<AtomicUsize>,
     semaphore: Arc<Semaphore>,
+    #[allow(dead_code)]
     health_check: Option<surf::Request>,
+    hosts: Option<Arc<HostPolicy>>,
+    upload: Option<Arc<TokenBucket>>,
+    download: Option<Arc<TokenBucket>>,
+    templates: Arc<Templates>,
+    usage: Arc<Usage>,
+    middleware: Arc<SlotState>,
 }
 
 /// The builder struct, used to create a SurfPool
 #[derive(Debug, Default)]
 pub struct SurfPoolBuilder {
     size: usize,
+    max_concurrency: usize,
     health_check: Option<surf::RequestBuilder>,
     pre_connect: bool,
+    stateless: bool,
+    host_limits: Option<HostLimits>,
+    host_overrides: HashMap<String, HostLimits>,
+    max_upload_rate: u64,
+    max_download_rate: u64,
 }
 
 #[derive(Debug, Error)]
 pub enum SurfPoolError {
     #[error("Size {0} is not valid (0 < size < {})", MAX_POOL_SIZE)]
     SizeNotValid(usize),
+    #[error(
+        "Max concurrency {0} is not valid (size {1} <= max_concurrency <= {})",
+        MAX_CONCURRENCY
+    )]
+    ConcurrencyNotValid(usize, usize),
+    #[error("Host concurrency {0} is not valid (it cannot be 0)")]
+    HostLimitNotValid(usize),
+    #[error("Slot {0} is not valid (slot < {1})")]
+    SlotNotValid(usize, usize),
+    #[error("Template {0} is not registered")]
+    TemplateNotFound(String),
+    #[error("Template parameter {0} is missing")]
+    TemplateParamMissing(String),
+    #[error("Template parameter {0} is not in the template")]
+    TemplateParamNotValid(String),
+    #[error("Template {0} has a body (a template cannot have a body)")]
+    TemplateBodyNotValid(String),
+    #[error("Percentile {0} is not valid (0 < percentile < 1)")]
+    PercentileNotValid(f64),
 }
 
 impl SurfPoolBuilder {
@@ -47,9 +105,53 @@ impl SurfPoolBuilder {
         }
         Ok(SurfPoolBuilder {
             size,
+            max_concurrency: size,
             ..Default::default()
         })
     }
+    /// The max_concurrency is the number of handlers that can be used at the
+    /// same time; by default it's equal to the pool size
+    /// When all the clients are in use, the additional handlers share a clone
+    /// of one of them, together with its established connections
+    /// It cannot be lower than the pool size or bigger than 1000
+    ///
+    /// ```rust
+    /// use surf_pool::SurfPoolBuilder;
+    ///
+    /// let builder = SurfPoolBuilder::new(4)
+    ///     .unwrap()
+    ///     .max_concurrency(16)
+    ///     .unwrap();
+    /// ```
+    pub fn max_concurrency(mut self, max_concurrency: usize) -> Result<Self> {
+        if max_concurrency < self.size || max_concurrency > MAX_CONCURRENCY {
+            return Err(SurfPoolError::ConcurrencyNotValid(
+                max_concurrency,
+                self.size,
+            ));
+        }
+        self.max_concurrency = max_concurrency;
+        Ok(self)
+    }
+    /// If true, the pool hands out clones of its clients, guarded only by the
+    /// max_concurrency limit: there is no exclusive use of a client, hence
+    /// no per-slot lock, trading strict per-connection checkout for lower
+    /// contention
+    /// A Surf client clone shares the connections of the original client
+    ///
+    /// ```rust
+    /// use surf_pool::SurfPoolBuilder;
+    ///
+    /// let builder = SurfPoolBuilder::new(4)
+    ///     .unwrap()
+    ///     .max_concurrency(16)
+    ///     .unwrap()
+    ///     .stateless(true);
+    /// ```
+    pub fn stateless(mut self, stateless: bool) -> Self {
+        self.stateless = stateless;
+        self
+    }
     /// The health_check is a URL used to manage the connection
     /// It's used to check the connection health status, as keepalive and
     /// as pre-connect URL
@@ -81,6 +183,73 @@ impl SurfPoolBuilder {
         self.pre_connect = pre_connect;
         self
     }
+    /// The host_limits are applied to every target host, across all the
+    /// clients of the pool, even when the pool size is bigger
+    /// They are enforced only by the execution helpers, like [`SurfPool::send`]
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    /// use surf_pool::{HostLimits, SurfPoolBuilder};
+    ///
+    /// let builder = SurfPoolBuilder::new(10)
+    ///     .unwrap()
+    ///     .host_limits(HostLimits::new(2, Duration::from_millis(500)).unwrap());
+    /// ```
+    pub fn host_limits(mut self, limits: HostLimits) -> Self {
+        self.host_limits = Some(limits);
+        self
+    }
+    /// Override the host_limits for a specific host
+    /// If no host_limits are defined, only the overridden hosts are limited
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    /// use surf_pool::{HostLimits, SurfPoolBuilder};
+    ///
+    /// let builder = SurfPoolBuilder::new(10)
+    ///     .unwrap()
+    ///     .host_limits(HostLimits::new(2, Duration::from_millis(500)).unwrap())
+    ///     .host_limits_for("httpbin.org", HostLimits::new(1, Duration::from_secs(1)).unwrap());
+    /// ```
+    pub fn host_limits_for(mut self, host: &str, limits: HostLimits) -> Self {
+        self.host_overrides
+            .insert(host.to_ascii_lowercase(), limits);
+        self
+    }
+    /// The max_upload_rate is the aggregate upload bandwidth, in bytes per
+    /// second, shared by all the clients of the pool
+    /// It's enforced only by the execution helpers, like [`SurfPool::send`],
+    /// while the request body is sent
+    /// A rate of 0 disables the cap, that is the default
+    ///
+    /// ```rust
+    /// use surf_pool::SurfPoolBuilder;
+    ///
+    /// let builder = SurfPoolBuilder::new(3)
+    ///     .unwrap()
+    ///     .max_upload_rate(512 * 1024);
+    /// ```
+    pub fn max_upload_rate(mut self, bytes_per_second: u64) -> Self {
+        self.max_upload_rate = bytes_per_second;
+        self
+    }
+    /// The max_download_rate is the aggregate download bandwidth, in bytes
+    /// per second, shared by all the clients of the pool
+    /// It's enforced only by the execution helpers, like [`SurfPool::send`],
+    /// while the response body is received
+    /// A rate of 0 disables the cap, that is the default
+    ///
+    /// ```rust
+    /// use surf_pool::SurfPoolBuilder;
+    ///
+    /// let builder = SurfPoolBuilder::new(3)
+    ///     .unwrap()
+    ///     .max_download_rate(2 * 1024 * 1024);
+    /// ```
+    pub fn max_download_rate(mut self, bytes_per_second: u64) -> Self {
+        self.max_download_rate = bytes_per_second;
+        self
+    }
     /// The build function that creates the @SurfPool
     /// If a health_check is available and pre_connect is set to true
     /// the connections are established in this function
@@ -96,40 +265,121 @@ impl SurfPoolBuilder {
     /// ```
     pub async fn build(self) -> SurfPool {
         let mut pool = Vec::with_capacity(self.size);
+        let mut clients = Vec::with_capacity(self.size);
         for _ in 0..self.size {
-            let m = Arc::new(Mutex::new(Client::new()));
-            pool.push(m.clone());
+            let c = Client::new();
+            if !self.stateless {
+                let m = Arc::new(Mutex::new(c.clone()));
+                pool.push(m.clone());
+            }
+            clients.push(c);
         }
+        let max_concurrency = self.max_concurrency;
+        let slots: Vec<Arc<SlotState>> = (0..self.size).map(|_| Arc::default()).collect();
         let health_check = if let Some(req) = self.health_check {
             let req = req.build();
 
             if self.pre_connect {
-                for m in &pool {
-                    let c = m.lock().await;
-                    c.recv_bytes(req.clone()).await.unwrap_or_default();
+                for (c, state) in clients.iter().zip(&slots) {
+                    if let Err(e) = c.recv_bytes(req.clone()).await {
+                        state.record_error(ErrorKind::HealthCheck, e);
+                    }
                 }
             }
             Some(req)
         } else {
             None
         };
+        let hosts = if self.host_limits.is_some() || !self.host_overrides.is_empty() {
+            Some(Arc::new(HostPolicy::new(
+                self.host_limits,
+                self.host_overrides,
+            )))
+        } else {
+            None
+        };
         SurfPool {
             pool,
-            semaphore: Arc::new(Semaphore::new(self.size)),
+            clients,
+            slots,
+            max_concurrency,
+            next_shared: Arc::default(),
+            semaphore: Arc::new(Semaphore::new(max_concurrency)),
             health_check,
+            hosts,
+            upload: bucket(self.max_upload_rate),
+            download: bucket(self.max_download_rate),
+            templates: Arc::default(),
+            usage: Arc::default(),
+            middleware: Arc::default(),
         }
     }
 }
 
+fn bucket(rate: u64) -> Option<Arc<TokenBucket>> {
+    if rate == 0 {
+        None
+    } else {
+        Some(Arc::new(TokenBucket::new(rate)))
+    }
+}
+
+fn body_from<R>(reader: R, len: Option<usize>, mime: surf::http::Mime) -> surf::Body
+where
+    R: async_std::io::Read + Unpin + Send + Sync + 'static,
+{
+    let mut body = surf::Body::from_reader(async_std::io::BufReader::new(reader), len);
+    body.set_mime(mime);
+    body
+}
+
+#[derive(Debug)]
+enum Checkout {
+    /// The client of the slot is used exclusively by the handler
+    Dedicated(MutexGuardArc<Client>),
+    /// A clone of the client of the slot, shared with other handlers
+    Shared(Client),
+}
+
 #[derive(Debug)]
 pub struct Handler {
-    sg: SemaphoreGuardArc,
-    mg: MutexGuardArc<Client>,
+    _permit: Permit,
+    checkout: Checkout,
+    slot: usize,
+    state: Arc<SlotState>,
+}
+
+impl Drop for Handler {
+    fn drop(&mut self) {
+        match self.checkout {
+            Checkout::Dedicated(_) => self.state.set_in_use(false),
+            Checkout::Shared(_) => self.state.release_shared(),
+        }
+    }
+}
+
+/// Reserve a slot, until it's dropped
+struct Reservation<'a>(&'a SlotState);
+
+impl<'a> Reservation<'a> {
+    fn new(state: &'a SlotState) -> Self {
+        state.reserve();
+        Reservation(state)
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.0.unreserve();
+    }
 }
 
 impl SurfPool {
     pub fn get_pool_size(&self) -> usize {
-        self.pool.len()
+        self.clients.len()
+    }
+    pub fn get_max_concurrency(&self) -> usize {
+        self.max_concurrency
     }
     /// This function return an handler representing a potential connection
     /// available in the pool.
@@ -139,9 +389,11 @@ impl SurfPool {
     /// available again
     /// To not starve other clients, it's important to drop the handler after
     /// it has been used
-    /// The return type is an [`Option`], but it should never return `None`,
-    /// the system is designed in a way that, once unblocked, at least one
-    /// resources should be available
+    /// The system is designed in a way that, once unblocked, at least one
+    /// resources should be available; if a slot is only briefly locked by
+    /// [`SurfPool::get_handler_for_slot`], the function tries again
+    /// If max_concurrency is bigger than the pool size, and all the clients
+    /// are in use, the handler shares a clone of one of them
     /// ```rust
     /// # futures_lite::future::block_on( async {
     ///
@@ -153,18 +405,302 @@ impl SurfPool {
     /// # } )
     /// ```
     pub async fn get_handler(&self) -> Handler {
-        self.get_handler_option().await.unwrap()
+        let requested = Instant::now();
+        loop {
+            if let Some(handler) = self.get_handler_option(requested).await {
+                return handler;
+            }
+            async_std::task::yield_now().await;
+        }
     }
 
-    async fn get_handler_option(&self) -> Option<Handler> {
+    async fn get_handler_option(&self, requested: Instant) -> Option<Handler> {
         let sg = self.semaphore.acquire_arc(1).await.unwrap();
-        for m in &self.pool {
+        for (slot, m) in self.pool.iter().enumerate() {
+            if self.slots[slot].is_reserved() {
+                continue;
+            }
             if let Some(mg) = m.try_lock_arc() {
-                return Some(Handler { sg, mg });
+                return Some(self.new_handler(sg, Checkout::Dedicated(mg), slot, requested));
             }
         }
+        if self.max_concurrency > self.pool.len() {
+            // In stateless mode the pool has no locks, every handler is shared
+            let slot = self.next_shared.fetch_add(1, Ordering::Relaxed) % self.clients.len();
+            let c = self.clients[slot].clone();
+            return Some(self.new_handler(sg, Checkout::Shared(c), slot, requested));
+        }
         None
     }
+    /// This function return the handler of a specific slot of the pool,
+    /// useful for tests and maintenance tasks that need to use a particular
+    /// client
+    /// If the slot is in use, the function will wait until it's released
+    /// In stateless mode, the handler shares a clone of the client of the
+    /// slot, and it's returned as soon as the max_concurrency allows it
+    /// An error is returned if the slot doesn't exist
+    /// ```rust
+    /// # futures_lite::future::block_on( async {
+    ///
+    /// use surf_pool::SurfPoolBuilder;
+    ///
+    /// let builder = SurfPoolBuilder::new(3).unwrap();
+    /// let pool = builder.build().await;
+    /// let handler = pool.get_handler_for_slot(2).await.unwrap();
+    /// assert_eq!(handler.get_slot(), 2);
+    /// # } )
+    /// ```
+    pub async fn get_handler_for_slot(&self, slot: usize) -> Result<Handler> {
+        if slot >= self.clients.len() {
+            return Err(SurfPoolError::SlotNotValid(slot, self.clients.len()));
+        }
+        let requested = Instant::now();
+        let m = match self.pool.get(slot) {
+            Some(m) => m,
+            None => {
+                let sg = self.semaphore.acquire_arc(1).await.unwrap();
+                let c = self.clients[slot].clone();
+                return Ok(self.new_handler(sg, Checkout::Shared(c), slot, requested));
+            }
+        };
+        // The slot is reserved while waiting, so that get_handler doesn't
+        // take it again once it's released
+        let _reservation = Reservation::new(&self.slots[slot]);
+        // The permit is acquired only once the slot is locked, to not block
+        // the other handlers while waiting; in the meantime, get_handler
+        // skips the slot and tries again
+        let mg = m.lock_arc().await;
+        let sg = self.semaphore.acquire_arc(1).await.unwrap();
+        Ok(self.new_handler(sg, Checkout::Dedicated(mg), slot, requested))
+    }
+
+    fn new_handler(
+        &self,
+        sg: SemaphoreGuardArc,
+        checkout: Checkout,
+        slot: usize,
+        requested: Instant,
+    ) -> Handler {
+        let state = self.slots[slot].clone();
+        match checkout {
+            Checkout::Dedicated(_) => state.set_in_use(true),
+            Checkout::Shared(_) => state.acquire_shared(),
+        }
+        Handler {
+            _permit: Permit::new(sg, requested, self.usage.clone()),
+            checkout,
+            slot,
+            state,
+        }
+    }
+    /// Wait for a permit of the max_concurrency, without taking a client
+    pub(crate) async fn acquire_permit(&self) -> Permit {
+        let requested = Instant::now();
+        let sg = self.semaphore.acquire_arc(1).await.unwrap();
+        Permit::new(sg, requested, self.usage.clone())
+    }
+    /// This function sends the request using one of the clients of the pool
+    /// The response body is streamed: the client and the host limits are
+    /// held until it's fully received, or the response is dropped
+    /// Both request and response bodies are subject to the bandwidth caps
+    /// ```rust
+    /// # futures_lite::future::block_on( async {
+    ///
+    /// use surf_pool::SurfPoolBuilder;
+    ///
+    /// let builder = SurfPoolBuilder::new(3).unwrap();
+    /// let pool = builder.build().await;
+    /// pool.send(surf::get("https://httpbin.org")).await;
+    /// # } )
+    /// ```
+    pub async fn send(&self, req: impl Into<surf::Request>) -> surf::Result<surf::Response> {
+        let (req, sent, host_permit) = self.prepare(req.into()).await?;
+        let handler = match &host_permit {
+            Some(host_permit) => host_permit.start_with(|| self.get_handler()).await,
+            None => self.get_handler().await,
+        };
+        let res = handler.get_client().send(req).await;
+        let state = handler.state.clone();
+        self.complete(state, sent, res, (handler, host_permit))
+    }
+    /// Apply the pool policies before sending the request: it waits for the
+    /// host concurrency, and the request body is wrapped to be metered and
+    /// throttled by the upload bandwidth while it's sent
+    /// It returns the request, the counter of the body bytes sent and the
+    /// host permit, that has to be started while acquiring the resource used
+    /// to send the request, and held until the request is completed
+    pub(crate) async fn prepare(
+        &self,
+        mut req: surf::Request,
+    ) -> surf::Result<(surf::Request, Arc<AtomicU64>, Option<HostPermit>)> {
+        let host_permit = match &self.hosts {
+            Some(hosts) => hosts.acquire(req.url()).await,
+            None => None,
+        };
+        let sent = Arc::new(AtomicU64::new(0));
+        let body = req.take_body();
+        if body.is_empty() != Some(true) {
+            let mime = body.mime().clone();
+            let len = body.len();
+            let reader = MeteredReader::new(body, self.upload.clone(), sent.clone());
+            req.set_body(body_from(reader, len, mime));
+        }
+        Ok((req, sent, host_permit))
+    }
+    /// Apply the pool policies after the request has been sent: it updates
+    /// the statistics in state, and the response body is wrapped to be
+    /// received within the download bandwidth
+    /// The guard holds the resources used to send the request, and it's
+    /// released once the response body is fully received, or dropped
+    pub(crate) fn complete<G>(
+        &self,
+        state: Arc<SlotState>,
+        sent: Arc<AtomicU64>,
+        res: surf::Result<surf::Response>,
+        guard: G,
+    ) -> surf::Result<surf::Response>
+    where
+        G: Unpin + Send + Sync + 'static,
+    {
+        // The body may be sent even if the request fails
+        state.add_bytes_sent(sent.load(Ordering::Relaxed));
+        let mut res = state.check(ErrorKind::Request, res)?;
+        if res.status().is_server_error() {
+            let status = res.status();
+            let message = format!("{} {}", status, status.canonical_reason());
+            state.record_error(ErrorKind::Status(status.into()), message);
+        }
+        let body = res.take_body();
+        let mime = body.mime().clone();
+        let len = body.len();
+        let reader = ResponseReader::new(body, self.download.clone(), state, guard);
+        res.set_body(body_from(reader, len, mime));
+        Ok(res)
+    }
+    /// Like [`SurfPool::send`], but it returns the response body as bytes
+    pub async fn recv_bytes(&self, req: impl Into<surf::Request>) -> surf::Result<Vec<u8>> {
+        self.send(req).await?.body_bytes().await
+    }
+    /// Like [`SurfPool::send`], but it returns the response body as string
+    pub async fn recv_string(&self, req: impl Into<surf::Request>) -> surf::Result<String> {
+        self.send(req).await?.body_string().await
+    }
+    /// This function registers a named request template, that can be
+    /// executed via [`SurfPool::execute`]
+    /// Every `{param}` in the url is replaced with the parameters provided
+    /// at execution time
+    /// The request body is not part of the template: an error is returned if
+    /// the request has one, and the template is not registered
+    /// Registering a template with an existing name replaces it
+    /// ```rust
+    /// # futures_lite::future::block_on( async {
+    ///
+    /// use surf_pool::SurfPoolBuilder;
+    ///
+    /// let builder = SurfPoolBuilder::new(3).unwrap();
+    /// let pool = builder.build().await;
+    /// pool.register("get_user", surf::get("https://httpbin.org/anything/users/{id}"))
+    ///     .unwrap();
+    /// # } )
+    /// ```
+    pub fn register(&self, name: &str, req: impl Into<surf::Request>) -> Result<()> {
+        self.templates.register(name, req.into())
+    }
+    /// This function executes a named request template, via [`SurfPool::send`]
+    /// An error is returned if the template is not registered, if a
+    /// placeholder has no parameter or if a parameter has no placeholder
+    /// ```rust
+    /// # futures_lite::future::block_on( async {
+    ///
+    /// use surf_pool::SurfPoolBuilder;
+    ///
+    /// let builder = SurfPoolBuilder::new(3).unwrap();
+    /// let pool = builder.build().await;
+    /// pool.register("get_user", surf::get("https://httpbin.org/anything/users/{id}"))
+    ///     .unwrap();
+    /// pool.execute("get_user", &[("id", "42")]).await;
+    /// # } )
+    /// ```
+    pub async fn execute(
+        &self,
+        name: &str,
+        params: &[(&str, &str)],
+    ) -> surf::Result<surf::Response> {
+        let (req, counters) = self
+            .templates
+            .request(name, params)
+            .ok_or_else(|| SurfPoolError::TemplateNotFound(name.to_string()))?;
+        let res = match req {
+            Ok(req) => self.send(req).await,
+            Err(e) => Err(e),
+        };
+        let failed = match &res {
+            Ok(res) => res.status().is_client_error() || res.status().is_server_error(),
+            Err(_) => true,
+        };
+        counters.record(failed);
+        res
+    }
+    /// This function returns the counters of a named request template, or
+    /// `None` if the template is not registered
+    pub fn template_stats(&self, name: &str) -> Option<TemplateStats> {
+        self.templates.stats(name)
+    }
+    /// This function returns the statistics of the pool: for every slot, if
+    /// it's in use, the body bytes transferred and the most recent error
+    /// observed by the execution helpers, the same for the requests routed
+    /// via [`PoolMiddleware`], and the counters of the named request templates
+    /// ```rust
+    /// # futures_lite::future::block_on( async {
+    ///
+    /// use surf_pool::SurfPoolBuilder;
+    ///
+    /// let builder = SurfPoolBuilder::new(3).unwrap();
+    /// let pool = builder.build().await;
+    /// let stats = pool.stats();
+    /// assert_eq!(stats.slots.len(), 3);
+    /// assert!(stats.slots[0].last_error.is_none());
+    /// assert_eq!(stats.bytes_received(), 0);
+    /// # } )
+    /// ```
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            slots: self
+                .slots
+                .iter()
+                .enumerate()
+                .map(|(slot, state)| state.stats(slot))
+                .collect(),
+            middleware: self.middleware.middleware_stats(),
+            templates: self.templates.all_stats(),
+        }
+    }
+    /// This function analyzes the wait and hold times of the most recent
+    /// handlers, and requests routed via [`PoolMiddleware`], and recommends
+    /// the smallest max_concurrency that keeps the wait time below
+    /// target_wait for the given percentile of the requests
+    /// The report includes the assumptions used for the recommendation
+    /// ```rust
+    /// # futures_lite::future::block_on( async {
+    ///
+    /// use std::time::Duration;
+    /// use surf_pool::SurfPoolBuilder;
+    ///
+    /// let builder = SurfPoolBuilder::new(3).unwrap();
+    /// let pool = builder.build().await;
+    /// drop(pool.get_handler().await);
+    /// let report = pool.sizing_report(0.99, Duration::from_millis(10)).unwrap();
+    /// println!("recommended size: {}", report.recommended_size);
+    /// # } )
+    /// ```
+    pub fn sizing_report(&self, percentile: f64, target_wait: Duration) -> Result<SizingReport> {
+        if !(percentile > 0.0 && percentile < 1.0) {
+            return Err(SurfPoolError::PercentileNotValid(percentile));
+        }
+        Ok(self
+            .usage
+            .report(self.max_concurrency, percentile, target_wait))
+    }
 }
 
 impl Handler {
@@ -188,13 +724,405 @@ impl Handler {
     /// # } )
     /// ```
     pub fn get_client(&self) -> &Client {
-        &*self.mg
+        match &self.checkout {
+            Checkout::Dedicated(mg) => mg,
+            Checkout::Shared(c) => c,
+        }
+    }
+    /// This function returns the index of the slot used by the handler
+    pub fn get_slot(&self) -> usize {
+        self.slot
+    }
+    /// This function returns true if the handler shares the client of the
+    /// slot with other handlers; it can happen only if max_concurrency is
+    /// bigger than the pool size, or in stateless mode
+    pub fn is_shared(&self) -> bool {
+        matches!(self.checkout, Checkout::Shared(_))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use async_std::future::timeout;
+    use std::time::Duration;
+
+    /// Serve a single request, reading a body of `body_len` bytes equal to 1,
+    /// then reply with `reply`, or close the connection if it's `None`
+    pub(crate) async fn serve_once(body_len: usize, reply: Option<&'static str>) -> String {
+        serve_status_once("200 OK", body_len, reply).await
+    }
+
+    /// Like serve_once, but the reply has the given `status` line
+    async fn serve_status_once(
+        status: &'static str,
+        body_len: usize,
+        reply: Option<&'static str>,
+    ) -> String {
+        use async_std::io::{ReadExt, WriteExt};
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        async_std::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let mut received = 0;
+            while received < body_len {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                received += buf[..n].iter().filter(|b| **b == 1).count();
+            }
+            if let Some(reply) = reply {
+                let res = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    reply.len(),
+                    reply
+                );
+                stream.write_all(res.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    #[async_std::test]
+    async fn upload_throttled() {
+        let uut = SurfPoolBuilder::new(1)
+            .unwrap()
+            .max_upload_rate(1000)
+            .build()
+            .await;
+        let url = serve_once(1500, Some("ok")).await;
+        let start = Instant::now();
+        let body = uut
+            .recv_string(surf::post(url).body(vec![1u8; 1500]))
+            .await
+            .unwrap();
+        assert_eq!(body, "ok");
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(uut.stats().bytes_sent(), 1500);
+        assert_eq!(uut.stats().bytes_received(), 2);
+    }
+
+    #[async_std::test]
+    async fn download_streamed() {
+        let uut = SurfPoolBuilder::new(1)
+            .unwrap()
+            .max_download_rate(1000)
+            .build()
+            .await;
+        let reply: &'static str = Box::leak("x".repeat(1500).into_boxed_str());
+        let url = serve_once(0, Some(reply)).await;
+        // The response is returned before the body is received
+        let mut res = timeout(Duration::from_millis(300), uut.send(surf::get(url)))
+            .await
+            .unwrap()
+            .unwrap();
+        // The client is held until the body is received
+        let blocked = timeout(Duration::from_millis(50), uut.get_handler()).await;
+        assert!(blocked.is_err());
+        let start = Instant::now();
+        assert_eq!(res.body_string().await.unwrap(), reply);
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(uut.stats().bytes_received(), 1500);
+        let available = timeout(Duration::from_millis(50), uut.get_handler()).await;
+        assert!(available.is_ok());
+    }
+
+    #[async_std::test]
+    async fn status_error() {
+        let uut = SurfPoolBuilder::new(1).unwrap().build().await;
+        let url = serve_status_once("503 Service Unavailable", 0, Some("")).await;
+        let res = uut.send(surf::get(url)).await.unwrap();
+        assert_eq!(res.status(), 503);
+        let last_error = uut.stats().slots[0].last_error.clone().unwrap();
+        assert_eq!(last_error.kind, ErrorKind::Status(503));
+        assert_eq!(last_error.message, "503 Service Unavailable");
+    }
+
+    #[async_std::test]
+    async fn bytes_sent_on_failure() {
+        let uut = SurfPoolBuilder::new(1).unwrap().build().await;
+        let url = serve_once(1000, None).await;
+        assert!(uut
+            .send(surf::post(url).body(vec![1u8; 1000]))
+            .await
+            .is_err());
+        let stats = uut.stats();
+        assert_eq!(stats.bytes_sent(), 1000);
+        assert_eq!(stats.slots[0].errors, 1);
+    }
+
+    #[async_std::test]
+    async fn host_interval_on_busy_pool() {
+        let uut = SurfPoolBuilder::new(1)
+            .unwrap()
+            .host_limits(HostLimits::new(10, Duration::from_millis(100)).unwrap())
+            .build()
+            .await;
+        let handler = uut.get_handler().await;
+        let tasks: Vec<_> = (0..3)
+            .map(|_| {
+                let uut = uut.clone();
+                async_std::task::spawn(async move {
+                    uut.send(surf::get("http://127.0.0.1:1/")).await.ok();
+                })
+            })
+            .collect();
+        // The requests are waiting for the pool: the interval is not consumed
+        async_std::task::sleep(Duration::from_millis(300)).await;
+        let released = Instant::now();
+        drop(handler);
+        for t in tasks {
+            t.await;
+        }
+        assert!(released.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[async_std::test]
+    async fn host_interval_samples() {
+        let uut = SurfPoolBuilder::new(2)
+            .unwrap()
+            .host_limits(HostLimits::new(10, Duration::from_millis(100)).unwrap())
+            .build()
+            .await;
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let uut = uut.clone();
+                async_std::task::spawn(async move {
+                    uut.send(surf::get("http://127.0.0.1:1/")).await.ok();
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.await;
+        }
+        // Only the handlers used to send a request are sampled
+        let report = uut.sizing_report(0.9, Duration::from_millis(1)).unwrap();
+        assert_eq!(report.samples, 4);
+    }
+
+    #[async_std::test]
+    async fn host_interval_not_blocking() {
+        let uut = SurfPoolBuilder::new(1)
+            .unwrap()
+            .host_limits_for(
+                "127.0.0.1",
+                HostLimits::new(10, Duration::from_secs(1)).unwrap(),
+            )
+            .build()
+            .await;
+        assert!(uut.send(surf::get("http://127.0.0.1:1/")).await.is_err());
+        let limited = {
+            let uut = uut.clone();
+            async_std::task::spawn(async move {
+                uut.send(surf::get("http://127.0.0.1:1/")).await.ok();
+            })
+        };
+        async_std::task::sleep(Duration::from_millis(50)).await;
+        // The only client is not held while waiting for the interval
+        let other = timeout(
+            Duration::from_millis(300),
+            uut.send(surf::get("http://localhost:1/")),
+        )
+        .await;
+        assert!(other.is_ok());
+        let start = Instant::now();
+        limited.await;
+        assert!(start.elapsed() >= Duration::from_millis(500));
+    }
+
+    #[async_std::test]
+    async fn template_not_found() {
+        let uut = SurfPoolBuilder::new(1).unwrap().build().await;
+        assert!(uut.execute("get_user", &[]).await.is_err());
+        assert_eq!(uut.template_stats("get_user"), None);
+        uut.register("get_user", surf::get("https://example.com/users/{id}"))
+            .unwrap();
+        assert_eq!(
+            uut.template_stats("get_user"),
+            Some(TemplateStats::default())
+        );
+        assert!(uut.execute("get_user", &[]).await.is_err());
+        assert_eq!(
+            uut.template_stats("get_user"),
+            Some(TemplateStats {
+                executions: 1,
+                failures: 1
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn stats_in_use_and_last_error() {
+        let uut = SurfPoolBuilder::new(2).unwrap().build().await;
+        let h = uut.get_handler_for_slot(1).await.unwrap();
+        let stats = uut.stats();
+        assert!(!stats.slots[0].in_use);
+        assert!(stats.slots[1].in_use);
+        drop(h);
+        assert!(!uut.stats().slots[1].in_use);
+        assert!(uut.send(surf::get("http://127.0.0.1:1/")).await.is_err());
+        let stats = uut.stats();
+        assert_eq!(stats.slots[0].errors, 1);
+        let last_error = stats.slots[0].last_error.as_ref().unwrap();
+        assert_eq!(last_error.kind, ErrorKind::Request);
+        assert!(stats.slots[1].last_error.is_none());
+    }
+
+    #[async_std::test]
+    async fn sizing_report() {
+        let uut = SurfPoolBuilder::new(2).unwrap().build().await;
+        assert!(uut.sizing_report(1.0, Duration::from_millis(1)).is_err());
+        for _ in 0..3 {
+            let h = uut.get_handler().await;
+            async_std::task::sleep(Duration::from_millis(10)).await;
+            drop(h);
+        }
+        let report = uut.sizing_report(0.9, Duration::from_millis(1)).unwrap();
+        assert_eq!(report.samples, 3);
+        assert_eq!(report.current_size, 2);
+        assert!(report.mean_hold >= Duration::from_millis(10));
+        assert!(report.utilization > 0.0 && report.utilization <= 1.0);
+        assert!(report.recommended_size >= 1);
+        assert!(!report.assumptions.is_empty());
+    }
+
+    #[async_std::test]
+    async fn max_concurrency() {
+        let uut = SurfPoolBuilder::new(2)
+            .unwrap()
+            .max_concurrency(4)
+            .unwrap()
+            .build()
+            .await;
+        assert_eq!(uut.get_pool_size(), 2);
+        assert_eq!(uut.get_max_concurrency(), 4);
+        let mut handlers = Vec::new();
+        for _ in 0..4 {
+            handlers.push(uut.get_handler().await);
+        }
+        assert!(!handlers[0].is_shared());
+        assert!(!handlers[1].is_shared());
+        assert!(handlers[2].is_shared());
+        assert!(handlers[3].is_shared());
+        assert_eq!(uut.stats().slots[0].shared, 1);
+        let blocked = timeout(Duration::from_millis(50), uut.get_handler()).await;
+        assert!(blocked.is_err());
+        handlers.truncate(2);
+        let stats = uut.stats();
+        assert!(stats.slots.iter().all(|s| s.in_use && s.shared == 0));
+        assert!(SurfPoolBuilder::new(2).unwrap().max_concurrency(1).is_err());
+        assert!(SurfPoolBuilder::new(2)
+            .unwrap()
+            .max_concurrency(MAX_CONCURRENCY + 1)
+            .is_err());
+        assert_eq!(
+            SurfPoolBuilder::new(2)
+                .unwrap()
+                .build()
+                .await
+                .get_max_concurrency(),
+            2
+        );
+    }
+
+    #[async_std::test]
+    async fn stateless() {
+        let uut = SurfPoolBuilder::new(2)
+            .unwrap()
+            .max_concurrency(3)
+            .unwrap()
+            .stateless(true)
+            .build()
+            .await;
+        assert_eq!(uut.get_pool_size(), 2);
+        let h0 = uut.get_handler().await;
+        let h1 = uut.get_handler().await;
+        let h2 = uut.get_handler_for_slot(1).await.unwrap();
+        assert!(h0.is_shared() && h1.is_shared() && h2.is_shared());
+        assert_ne!(h0.get_slot(), h1.get_slot());
+        assert_eq!(h2.get_slot(), 1);
+        let stats = uut.stats();
+        assert!(stats.slots.iter().all(|s| !s.in_use));
+        assert_eq!(stats.slots[1].shared, 2);
+        let blocked = timeout(Duration::from_millis(50), uut.get_handler()).await;
+        assert!(blocked.is_err());
+        assert!(uut.get_handler_for_slot(2).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn handler_for_slot() {
+        let uut = SurfPoolBuilder::new(3).unwrap().build().await;
+        assert!(matches!(
+            uut.get_handler_for_slot(3).await,
+            Err(SurfPoolError::SlotNotValid(3, 3))
+        ));
+        let h1 = uut.get_handler_for_slot(1).await.unwrap();
+        assert_eq!(h1.get_slot(), 1);
+        let blocked = timeout(Duration::from_millis(50), uut.get_handler_for_slot(1)).await;
+        assert!(blocked.is_err());
+        let h0 = uut.get_handler().await;
+        assert_eq!(h0.get_slot(), 0);
+        let h2 = uut.get_handler().await;
+        assert_eq!(h2.get_slot(), 2);
+        drop(h1);
+        let h1 = uut.get_handler_for_slot(1).await.unwrap();
+        assert_eq!(h1.get_slot(), 1);
+    }
+
+    #[async_std::test]
+    async fn handler_for_busy_slot() {
+        let uut = SurfPoolBuilder::new(2).unwrap().build().await;
+        let h1 = uut.get_handler_for_slot(1).await.unwrap();
+        let waiting = {
+            let uut = uut.clone();
+            async_std::task::spawn(async move { uut.get_handler_for_slot(1).await.unwrap() })
+        };
+        async_std::task::sleep(Duration::from_millis(20)).await;
+        let h0 = timeout(Duration::from_millis(50), uut.get_handler())
+            .await
+            .unwrap();
+        assert_eq!(h0.get_slot(), 0);
+        drop(h1);
+        let h1 = timeout(Duration::from_millis(50), waiting).await.unwrap();
+        assert_eq!(h1.get_slot(), 1);
+    }
+
+    #[async_std::test]
+    async fn handler_for_slot_under_contention() {
+        let uut = SurfPoolBuilder::new(2).unwrap().build().await;
+        let running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let uut = uut.clone();
+                let running = running.clone();
+                async_std::task::spawn(async move {
+                    while running.load(Ordering::Relaxed) {
+                        let h = uut.get_handler().await;
+                        async_std::task::sleep(Duration::from_millis(5)).await;
+                        drop(h);
+                    }
+                })
+            })
+            .collect();
+        async_std::task::sleep(Duration::from_millis(20)).await;
+        for _ in 0..5 {
+            let h0 = timeout(Duration::from_millis(200), uut.get_handler_for_slot(0))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(h0.get_slot(), 0);
+        }
+        running.store(false, Ordering::Relaxed);
+        for t in tasks {
+            t.await;
+        }
+    }
+
     #[async_std::test]
     async fn with_pre_connected_pool() {
         let builder = SurfPoolBuilder::new(3)
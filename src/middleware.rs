@@ -0,0 +1,132 @@
+//! Surf middleware routing requests through a pool
+use crate::stats::SlotState;
+use crate::SurfPool;
+use std::sync::Arc;
+use surf::middleware::{Middleware, Next};
+use surf::{Client, Request, Response};
+
+/// A Surf middleware that applies the policies of a [`SurfPool`] to the
+/// requests of an existing [`Client`]
+/// Every request waits for a permit of the pool max_concurrency, that is held
+/// until the response body is received, and it's subject to the host limits
+/// and the bandwidth caps; the response body is streamed, and the usage of
+/// the permit is part of [`crate::SurfPool::sizing_report`]
+/// The request is still sent by the client the middleware is attached to:
+/// the pooled clients are not used, and the statistics are reported in
+/// [`crate::PoolStats::middleware`] instead of any slot
+///
+/// ```rust
+/// # futures_lite::future::block_on( async {
+///
+/// use surf_pool::{PoolMiddleware, SurfPoolBuilder};
+///
+/// let pool = SurfPoolBuilder::new(3).unwrap().build().await;
+/// let client = surf::client().with(PoolMiddleware::new(pool));
+/// # } )
+/// ```
+#[derive(Clone, Debug)]
+pub struct PoolMiddleware {
+    pool: SurfPool,
+}
+
+impl PoolMiddleware {
+    pub fn new(pool: SurfPool) -> Self {
+        PoolMiddleware { pool }
+    }
+}
+
+#[surf::utils::async_trait]
+impl Middleware for PoolMiddleware {
+    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result<Response> {
+        let (req, sent, host_permit) = self.pool.prepare(req).await?;
+        let permit = match &host_permit {
+            Some(host_permit) => host_permit.start_with(|| self.pool.acquire_permit()).await,
+            None => self.pool.acquire_permit().await,
+        };
+        let in_flight = InFlight::new(self.pool.middleware.clone());
+        let res = next.run(req, client).await;
+        let state = self.pool.middleware.clone();
+        self.pool
+            .complete(state, sent, res, (permit, in_flight, host_permit))
+    }
+}
+
+/// Count a middleware request as in flight, until it's dropped
+struct InFlight(Arc<SlotState>);
+
+impl InFlight {
+    fn new(state: Arc<SlotState>) -> Self {
+        state.acquire_shared();
+        InFlight(state)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.release_shared();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::serve_once;
+    use crate::{ErrorKind, SurfPoolBuilder};
+    use async_std::future::timeout;
+    use std::time::Duration;
+
+    #[async_std::test]
+    async fn pool_policies() {
+        let pool = SurfPoolBuilder::new(1).unwrap().build().await;
+        let client = surf::client().with(PoolMiddleware::new(pool.clone()));
+        let handler = pool.get_handler().await;
+        // The permit is taken by the handler
+        let blocked = timeout(
+            Duration::from_millis(50),
+            client.get("http://127.0.0.1:1/").send(),
+        )
+        .await;
+        assert!(blocked.is_err());
+        drop(handler);
+        assert!(client.get("http://127.0.0.1:1/").await.is_err());
+        let stats = pool.stats();
+        assert_eq!(stats.slots[0].errors, 0);
+        assert_eq!(stats.middleware.errors, 1);
+        assert_eq!(stats.middleware.in_flight, 0);
+        assert_eq!(
+            stats.middleware.last_error.as_ref().unwrap().kind,
+            ErrorKind::Request
+        );
+        // The handler and the failed request; the timed out one never got a
+        // permit
+        let report = pool.sizing_report(0.9, Duration::from_millis(1)).unwrap();
+        assert_eq!(report.samples, 2);
+    }
+
+    #[async_std::test]
+    async fn streamed_response() {
+        let pool = SurfPoolBuilder::new(1)
+            .unwrap()
+            .max_download_rate(1000)
+            .build()
+            .await;
+        let client = surf::client().with(PoolMiddleware::new(pool.clone()));
+        let reply: &'static str = Box::leak("x".repeat(1500).into_boxed_str());
+        let url = serve_once(0, Some(reply)).await;
+        let mut res = timeout(Duration::from_millis(300), client.get(url).send())
+            .await
+            .unwrap()
+            .unwrap();
+        // The permit is held until the body is received
+        let blocked = timeout(Duration::from_millis(50), pool.get_handler()).await;
+        assert!(blocked.is_err());
+        assert_eq!(pool.stats().middleware.in_flight, 1);
+        assert_eq!(res.body_string().await.unwrap(), reply);
+        let stats = pool.stats();
+        assert_eq!(stats.middleware.in_flight, 0);
+        assert_eq!(stats.middleware.bytes_received, 1500);
+        let report = pool.sizing_report(0.9, Duration::from_millis(1)).unwrap();
+        assert_eq!(report.samples, 1);
+        assert!(report.mean_hold >= Duration::from_millis(400));
+    }
+}
@@ -0,0 +1,358 @@
+//! Usage samples and capacity planning
+use crate::MAX_CONCURRENCY;
+use async_weighted_semaphore::SemaphoreGuardArc;
+use std::fmt;
+use std::sync::atomic::{fence, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const MAX_SAMPLES: usize = 1024;
+
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    requested: Instant,
+    wait: Duration,
+    hold: Duration,
+}
+
+/// A sample stored as nanoseconds; requested is relative to the epoch of the
+/// ring, plus one, so that 0 marks an entry not written yet or being written
+#[derive(Debug, Default)]
+struct Entry {
+    requested: AtomicU64,
+    wait: AtomicU64,
+    hold: AtomicU64,
+}
+
+/// The most recent usages of the max_concurrency permits, collected when the
+/// permit is released
+/// The samples are stored in a lock-free ring buffer, the oldest one is
+/// overwritten when it's full
+pub(crate) struct Usage {
+    epoch: Instant,
+    next: AtomicUsize,
+    ring: Vec<Entry>,
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Usage {
+            epoch: Instant::now(),
+            next: AtomicUsize::new(0),
+            ring: (0..MAX_SAMPLES).map(|_| Entry::default()).collect(),
+        }
+    }
+}
+
+impl fmt::Debug for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Usage")
+            .field("samples", &self.len())
+            .finish()
+    }
+}
+
+/// A permit of the pool max_concurrency, held by an handler or by a request
+/// routed via the middleware; its usage is recorded when it's released
+#[derive(Debug)]
+pub(crate) struct Permit {
+    _sg: SemaphoreGuardArc,
+    requested: Instant,
+    acquired: Instant,
+    usage: Arc<Usage>,
+}
+
+impl Permit {
+    pub(crate) fn new(sg: SemaphoreGuardArc, requested: Instant, usage: Arc<Usage>) -> Self {
+        Permit {
+            _sg: sg,
+            requested,
+            acquired: Instant::now(),
+            usage,
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.usage.record(self.requested, self.acquired);
+    }
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_nanos().min(u64::MAX as u128) as u64
+}
+
+impl Usage {
+    /// The number of samples recorded, up to the ring capacity
+    fn len(&self) -> usize {
+        self.next.load(Ordering::Relaxed).min(MAX_SAMPLES)
+    }
+
+    pub(crate) fn record(&self, requested: Instant, acquired: Instant) {
+        let entry = &self.ring[self.next.fetch_add(1, Ordering::Relaxed) % MAX_SAMPLES];
+        // The entry is written as a seqlock: the marker is cleared before
+        // the fields, and set again after them
+        entry.requested.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        entry
+            .wait
+            .store(nanos(acquired.duration_since(requested)), Ordering::Relaxed);
+        entry
+            .hold
+            .store(nanos(acquired.elapsed()), Ordering::Relaxed);
+        let offset = nanos(requested.saturating_duration_since(self.epoch));
+        entry.requested.store(offset + 1, Ordering::Release);
+    }
+
+    /// The samples completely written; an entry overwritten while it's read
+    /// is skipped
+    fn samples(&self) -> Vec<Sample> {
+        self.ring
+            .iter()
+            .filter_map(|entry| {
+                let requested = entry.requested.load(Ordering::Acquire);
+                if requested == 0 {
+                    return None;
+                }
+                let wait = entry.wait.load(Ordering::Relaxed);
+                let hold = entry.hold.load(Ordering::Relaxed);
+                fence(Ordering::Acquire);
+                if entry.requested.load(Ordering::Relaxed) != requested {
+                    return None;
+                }
+                Some(Sample {
+                    requested: self.epoch + Duration::from_nanos(requested - 1),
+                    wait: Duration::from_nanos(wait),
+                    hold: Duration::from_nanos(hold),
+                })
+            })
+            .collect()
+    }
+
+    pub(crate) fn report(
+        &self,
+        current_size: usize,
+        percentile: f64,
+        target_wait: Duration,
+    ) -> SizingReport {
+        let samples = self.samples();
+        let mut report = SizingReport {
+            samples: samples.len(),
+            window: Duration::from_secs(0),
+            arrival_rate: 0.0,
+            mean_hold: Duration::from_secs(0),
+            offered_load: 0.0,
+            utilization: 0.0,
+            observed_wait: Duration::from_secs(0),
+            percentile,
+            target_wait,
+            current_size,
+            recommended_size: current_size,
+            assumptions: vec![
+                "arrivals are random (Poisson) and hold times exponentially distributed, \
+                 the pool is modeled as an M/M/c queue (Erlang C)"
+                    .to_string(),
+                "a caller waiting for an handler never gives up".to_string(),
+                "handlers and middleware requests still in flight are not part of the samples"
+                    .to_string(),
+            ],
+        };
+        let first = match samples.iter().map(|s| s.requested).min() {
+            Some(first) => first,
+            None => {
+                report
+                    .assumptions
+                    .push("no samples collected, the current size is kept".to_string());
+                return report;
+            }
+        };
+        // The window ends when the last handler is released, not when the
+        // report is generated: an idle period would dilute the load observed
+        let last = samples
+            .iter()
+            .map(|s| s.requested + s.wait + s.hold)
+            .max()
+            .unwrap_or(first);
+        report.window = last.saturating_duration_since(first);
+        let total_hold: Duration = samples.iter().map(|s| s.hold).sum();
+        report.mean_hold = total_hold / samples.len() as u32;
+        let window = report.window.as_secs_f64();
+        let mean_hold = report.mean_hold.as_secs_f64();
+        if window == 0.0 || mean_hold == 0.0 {
+            report
+                .assumptions
+                .push("the samples have no duration, the current size is kept".to_string());
+            return report;
+        }
+        report.arrival_rate = samples.len() as f64 / window;
+        report.offered_load = report.arrival_rate * mean_hold;
+        report.utilization = total_hold.as_secs_f64() / (window * current_size as f64);
+        let mut waits: Vec<Duration> = samples.iter().map(|s| s.wait).collect();
+        waits.sort();
+        let index = ((waits.len() as f64 * percentile).ceil() as usize).clamp(1, waits.len());
+        report.observed_wait = waits[index - 1];
+        report.assumptions.push(format!(
+            "the last {} handlers, used over {:.1}s, are representative of the load",
+            samples.len(),
+            window
+        ));
+        let target = target_wait.as_secs_f64() / mean_hold;
+        report.recommended_size = match (1..=MAX_CONCURRENCY)
+            .find(|&c| wait_probability(c, report.offered_load, target) <= 1.0 - percentile)
+        {
+            Some(c) => c,
+            None => {
+                report.assumptions.push(format!(
+                    "the target cannot be met within the maximum max_concurrency ({})",
+                    MAX_CONCURRENCY
+                ));
+                MAX_CONCURRENCY
+            }
+        };
+        report
+    }
+}
+
+/// The probability that an arrival has to wait at all, with `servers` clients
+/// and an offered load of `load` erlangs
+fn erlang_c(servers: usize, load: f64) -> f64 {
+    if load >= servers as f64 {
+        return 1.0;
+    }
+    let mut erlang_b = 1.0;
+    for k in 1..=servers {
+        erlang_b = load * erlang_b / (k as f64 + load * erlang_b);
+    }
+    servers as f64 * erlang_b / (servers as f64 - load * (1.0 - erlang_b))
+}
+
+/// The probability that an arrival waits longer than `target`, expressed in
+/// mean hold times
+fn wait_probability(servers: usize, load: f64, target: f64) -> f64 {
+    if load >= servers as f64 {
+        return 1.0;
+    }
+    erlang_c(servers, load) * (-(servers as f64 - load) * target).exp()
+}
+
+/// The pool size recommendation, returned by [`crate::SurfPool::sizing_report`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SizingReport {
+    /// The number of handler and middleware request usages analyzed
+    pub samples: usize,
+    /// The time span covered by the samples, from the first request to the
+    /// last release
+    pub window: Duration,
+    /// The handlers requested per second
+    pub arrival_rate: f64,
+    /// The average time an handler is held
+    pub mean_hold: Duration,
+    /// The average number of handlers in use, in erlangs
+    pub offered_load: f64,
+    /// The fraction of the pool in use over the window
+    pub utilization: f64,
+    /// The wait time observed at the requested percentile
+    pub observed_wait: Duration,
+    pub percentile: f64,
+    pub target_wait: Duration,
+    /// The current max_concurrency of the pool
+    pub current_size: usize,
+    /// The smallest max_concurrency that meets the target
+    pub recommended_size: usize,
+    /// The assumptions behind the recommendation
+    pub assumptions: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn erlang_c_values() {
+        assert!((erlang_c(2, 1.0) - 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!(erlang_c(2, 2.0), 1.0);
+        assert!(wait_probability(3, 1.0, 1.0) < erlang_c(3, 1.0));
+    }
+
+    #[test]
+    fn no_samples() {
+        let uut = Usage::default();
+        let report = uut.report(4, 0.99, Duration::from_millis(10));
+        assert_eq!(report.samples, 0);
+        assert_eq!(report.recommended_size, 4);
+    }
+
+    #[test]
+    fn saturated_pool() {
+        let uut = Usage::default();
+        std::thread::sleep(Duration::from_millis(100));
+        let now = Instant::now();
+        // Every handler waited for another one to be released
+        for i in 0..10 {
+            let requested = now - Duration::from_millis(100);
+            let acquired = now - Duration::from_millis(100 - i);
+            uut.record(requested, acquired);
+        }
+        let report = uut.report(1, 0.99, Duration::from_millis(1));
+        assert_eq!(report.samples, 10);
+        assert!(report.offered_load > 1.0);
+        assert!(report.recommended_size > 1);
+        assert!(report.observed_wait >= Duration::from_millis(9));
+    }
+
+    #[test]
+    fn idle_after_samples() {
+        let uut = Usage::default();
+        let start = Instant::now();
+        std::thread::sleep(Duration::from_millis(100));
+        for i in 0..10 {
+            let requested = start + Duration::from_millis(10 * i);
+            uut.record(requested, requested);
+        }
+        let report = uut.report(2, 0.9, Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(100));
+        let idle = uut.report(2, 0.9, Duration::from_millis(1));
+        assert_eq!(idle.window, report.window);
+        assert_eq!(idle.arrival_rate, report.arrival_rate);
+        assert_eq!(idle.recommended_size, report.recommended_size);
+    }
+
+    #[test]
+    fn target_not_met() {
+        let uut = Usage::default();
+        std::thread::sleep(Duration::from_millis(100));
+        let requested = Instant::now() - Duration::from_millis(100);
+        // More handlers in use than the maximum max_concurrency
+        for _ in 0..MAX_SAMPLES {
+            uut.record(requested, requested);
+        }
+        let report = uut.report(500, 0.99, Duration::from_millis(1));
+        assert!(report.offered_load > MAX_CONCURRENCY as f64);
+        assert_eq!(report.recommended_size, MAX_CONCURRENCY);
+    }
+
+    #[test]
+    fn debug_is_short() {
+        let uut = Usage::default();
+        let now = Instant::now();
+        uut.record(now, now);
+        assert_eq!(format!("{:?}", uut), "Usage { samples: 1 }");
+    }
+
+    #[test]
+    fn ring_overwrite() {
+        let uut = Usage::default();
+        let now = Instant::now();
+        for i in 0..MAX_SAMPLES as u64 + 10 {
+            uut.record(now, now + Duration::from_millis(i));
+        }
+        let samples = uut.samples();
+        assert_eq!(samples.len(), MAX_SAMPLES);
+        // The first 10 samples have been overwritten
+        assert_eq!(
+            samples.iter().map(|s| s.wait).min(),
+            Some(Duration::from_millis(10))
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! Pool statistics
+use crate::TemplateStats;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// The kind of an error observed on a slot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The health check failed during the pre-connection
+    HealthCheck,
+    /// The request failed before a response was received
+    Request,
+    /// The response body couldn't be received
+    Body,
+    /// The server replied with an error status code (5xx)
+    Status(u16),
+}
+
+/// The most recent error observed on a slot
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LastError {
+    pub kind: ErrorKind,
+    pub timestamp: SystemTime,
+    pub message: String,
+}
+
+/// The statistics of a single slot of the pool
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlotStats {
+    pub slot: usize,
+    /// If true, an handler is currently using the client of the slot
+    pub in_use: bool,
+    /// The number of handlers currently sharing a clone of the client
+    pub shared: usize,
+    /// The number of errors observed on the slot
+    pub errors: u64,
+    pub last_error: Option<LastError>,
+    /// The request body bytes sent by the slot
+    pub bytes_sent: u64,
+    /// The response body bytes received by the slot
+    pub bytes_received: u64,
+}
+
+/// The statistics of the requests routed via [`crate::PoolMiddleware`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MiddlewareStats {
+    /// The number of requests currently in flight
+    pub in_flight: usize,
+    /// The number of errors observed
+    pub errors: u64,
+    pub last_error: Option<LastError>,
+    /// The request body bytes sent
+    pub bytes_sent: u64,
+    /// The response body bytes received
+    pub bytes_received: u64,
+}
+
+/// The statistics of the pool, returned by [`crate::SurfPool::stats`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoolStats {
+    pub slots: Vec<SlotStats>,
+    pub middleware: MiddlewareStats,
+    pub templates: HashMap<String, TemplateStats>,
+}
+
+impl PoolStats {
+    /// The request body bytes sent by all the slots and the middleware
+    pub fn bytes_sent(&self) -> u64 {
+        self.slots.iter().map(|s| s.bytes_sent).sum::<u64>() + self.middleware.bytes_sent
+    }
+    /// The response body bytes received by all the slots and the middleware
+    pub fn bytes_received(&self) -> u64 {
+        self.slots.iter().map(|s| s.bytes_received).sum::<u64>() + self.middleware.bytes_received
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SlotState {
+    in_use: AtomicBool,
+    shared: AtomicUsize,
+    reserved: AtomicUsize,
+    errors: AtomicU64,
+    last_error: Mutex<Option<LastError>>,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl SlotState {
+    pub(crate) fn set_in_use(&self, in_use: bool) {
+        self.in_use.store(in_use, Ordering::Relaxed);
+    }
+
+    pub(crate) fn acquire_shared(&self) {
+        self.shared.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn release_shared(&self) {
+        self.shared.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// A reserved slot is skipped by [`crate::SurfPool::get_handler`], because
+    /// somebody is waiting for it via [`crate::SurfPool::get_handler_for_slot`]
+    pub(crate) fn reserve(&self) {
+        self.reserved.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn unreserve(&self) {
+        self.reserved.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_reserved(&self) -> bool {
+        self.reserved.load(Ordering::Relaxed) > 0
+    }
+
+    pub(crate) fn add_bytes_sent(&self, bytes: u64) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_bytes_received(&self, bytes: u64) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error(&self, kind: ErrorKind, message: impl Display) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        *self.last_error.lock().unwrap() = Some(LastError {
+            kind,
+            timestamp: SystemTime::now(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Record the error, if any, and pass the result through
+    pub(crate) fn check<T>(&self, kind: ErrorKind, result: surf::Result<T>) -> surf::Result<T> {
+        if let Err(e) = &result {
+            self.record_error(kind, e);
+        }
+        result
+    }
+
+    pub(crate) fn stats(&self, slot: usize) -> SlotStats {
+        SlotStats {
+            slot,
+            in_use: self.in_use.load(Ordering::Relaxed),
+            shared: self.shared.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            last_error: self.last_error.lock().unwrap().clone(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The middleware traffic is tracked as a slot, where the in flight
+    /// requests are counted as shared handlers
+    pub(crate) fn middleware_stats(&self) -> MiddlewareStats {
+        MiddlewareStats {
+            in_flight: self.shared.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            last_error: self.last_error.lock().unwrap().clone(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_error() {
+        let uut = SlotState::default();
+        assert_eq!(uut.stats(0).last_error, None);
+        uut.record_error(ErrorKind::Status(503), "Service Unavailable");
+        let result: surf::Result<()> = Err(surf::Error::from_str(500, "connection reset"));
+        assert!(uut.check(ErrorKind::Request, result).is_err());
+        let stats = uut.stats(0);
+        assert_eq!(stats.errors, 2);
+        let last_error = stats.last_error.unwrap();
+        assert_eq!(last_error.kind, ErrorKind::Request);
+        assert_eq!(last_error.message, "connection reset");
+    }
+
+    #[test]
+    fn bytes_totals() {
+        let s0 = SlotState::default();
+        let s1 = SlotState::default();
+        let middleware = SlotState::default();
+        middleware.add_bytes_sent(5);
+        s0.add_bytes_sent(10);
+        s0.add_bytes_received(100);
+        s1.add_bytes_received(50);
+        s1.add_bytes_received(50);
+        let uut = PoolStats {
+            slots: vec![s0.stats(0), s1.stats(1)],
+            middleware: middleware.middleware_stats(),
+            templates: HashMap::new(),
+        };
+        assert_eq!(uut.slots[1].bytes_received, 100);
+        assert_eq!(uut.bytes_sent(), 15);
+        assert_eq!(uut.bytes_received(), 200);
+    }
+}
@@ -0,0 +1,263 @@
+//! Named request templates
+use crate::SurfPoolError;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use surf::http::url::Position;
+use surf::{Request, Url};
+
+/// The counters collected for a named template
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TemplateStats {
+    /// The number of times the template has been executed
+    pub executions: u64,
+    /// The number of executions that failed, or got an error status code
+    pub failures: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TemplateCounters {
+    executions: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl TemplateCounters {
+    pub(crate) fn record(&self, failed: bool) {
+        self.executions.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn stats(&self) -> TemplateStats {
+        TemplateStats {
+            executions: self.executions.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Template {
+    req: Request,
+    counters: Arc<TemplateCounters>,
+}
+
+/// The templates registered on a pool, shared by all its clones
+#[derive(Debug, Default)]
+pub(crate) struct Templates {
+    inner: RwLock<HashMap<String, Template>>,
+}
+
+impl Templates {
+    /// A request with a body cannot be a template: the body would be lost
+    /// when the request is cloned on every execution
+    pub(crate) fn register(&self, name: &str, req: Request) -> Result<(), SurfPoolError> {
+        if req.is_empty() != Some(true) {
+            return Err(SurfPoolError::TemplateBodyNotValid(name.to_string()));
+        }
+        let template = Template {
+            req,
+            counters: Arc::default(),
+        };
+        self.inner
+            .write()
+            .unwrap()
+            .insert(name.to_string(), template);
+        Ok(())
+    }
+
+    /// Build the request of the template, replacing every `{param}` in the
+    /// url with its percent-encoded value
+    /// Every placeholder needs a parameter and every parameter needs a
+    /// placeholder, otherwise an error is returned
+    pub(crate) fn request(
+        &self,
+        name: &str,
+        params: &[(&str, &str)],
+    ) -> Option<(surf::Result<Request>, Arc<TemplateCounters>)> {
+        let inner = self.inner.read().unwrap();
+        let template = inner.get(name)?;
+        let mut req = template.req.clone();
+        let req = substitute(req.url(), params)
+            .map_err(From::from)
+            .and_then(|url| Url::parse(&url).map_err(From::from))
+            .map(|url| {
+                let inner: &mut surf::http::Request = req.as_mut();
+                *inner.url_mut() = url;
+                req
+            });
+        Some((req, template.counters.clone()))
+    }
+
+    pub(crate) fn stats(&self, name: &str) -> Option<TemplateStats> {
+        let inner = self.inner.read().unwrap();
+        inner.get(name).map(|t| t.counters.stats())
+    }
+
+    pub(crate) fn all_stats(&self) -> HashMap<String, TemplateStats> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .iter()
+            .map(|(name, t)| (name.clone(), t.counters.stats()))
+            .collect()
+    }
+}
+
+fn substitute(url: &Url, params: &[(&str, &str)]) -> Result<String, SurfPoolError> {
+    // Braces are percent-encoded while parsing only in the url path; the
+    // query and the fragment are used as written, so that encoded braces
+    // there are not mistaken for placeholders
+    let template = url[..Position::AfterPath]
+        .replace("%7B", "{")
+        .replace("%7b", "{")
+        .replace("%7D", "}")
+        .replace("%7d", "}")
+        + &url[Position::AfterPath..];
+    let mut used = vec![false; params.len()];
+    let mut url = String::with_capacity(template.len());
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let name = &rest[start + 1..end];
+        let (i, (_, value)) = params
+            .iter()
+            .enumerate()
+            .find(|(_, (key, _))| *key == name)
+            .ok_or_else(|| SurfPoolError::TemplateParamMissing(name.to_string()))?;
+        used[i] = true;
+        url.push_str(&rest[..start]);
+        url.push_str(&encode(value));
+        rest = &rest[end + 1..];
+    }
+    url.push_str(rest);
+    if let Some(((key, _), _)) = params.iter().zip(&used).find(|(_, used)| !**used) {
+        return Err(SurfPoolError::TemplateParamNotValid(key.to_string()));
+    }
+    Ok(url)
+}
+
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substitute_path_and_query() {
+        let uut = Templates::default();
+        uut.register(
+            "get_user",
+            surf::get("https://example.com/users/{id}?fields={fields}").build(),
+        )
+        .unwrap();
+        let (req, _) = uut
+            .request("get_user", &[("id", "42"), ("fields", "name,mail")])
+            .unwrap();
+        assert_eq!(
+            req.unwrap().url().as_str(),
+            "https://example.com/users/42?fields=name%2Cmail"
+        );
+        assert!(uut.request("get_group", &[]).is_none());
+    }
+
+    #[test]
+    fn unresolved_params() {
+        let uut = Templates::default();
+        uut.register(
+            "get_user",
+            surf::get("https://example.com/users/{id}").build(),
+        )
+        .unwrap();
+        let (req, _) = uut.request("get_user", &[]).unwrap();
+        assert_eq!(
+            req.unwrap_err().to_string(),
+            SurfPoolError::TemplateParamMissing("id".to_string()).to_string()
+        );
+        let (req, _) = uut
+            .request("get_user", &[("id", "42"), ("name", "pizzamig")])
+            .unwrap();
+        assert_eq!(
+            req.unwrap_err().to_string(),
+            SurfPoolError::TemplateParamNotValid("name".to_string()).to_string()
+        );
+        let (req, _) = uut.request("get_user", &[("id", "{id}")]).unwrap();
+        assert_eq!(
+            req.unwrap().url().as_str(),
+            "https://example.com/users/%7Bid%7D"
+        );
+    }
+
+    #[test]
+    fn encoded_braces_in_query() {
+        let uut = Templates::default();
+        uut.register(
+            "search",
+            surf::get("http://h/users/{id}?filter=%7B%22a%22%3A1%7D").build(),
+        )
+        .unwrap();
+        let (req, _) = uut.request("search", &[("id", "1")]).unwrap();
+        assert_eq!(
+            req.unwrap().url().as_str(),
+            "http://h/users/1?filter=%7B%22a%22%3A1%7D"
+        );
+    }
+
+    #[test]
+    fn body_not_valid() {
+        let uut = Templates::default();
+        let req = surf::post("https://example.com/users/{id}")
+            .body_json(&"pizzamig")
+            .unwrap()
+            .build();
+        assert!(matches!(
+            uut.register("add_user", req),
+            Err(SurfPoolError::TemplateBodyNotValid(name)) if name == "add_user"
+        ));
+        assert!(uut.request("add_user", &[]).is_none());
+        uut.register(
+            "add_user",
+            surf::post("https://example.com/users/{id}").build(),
+        )
+        .unwrap();
+        assert!(uut.request("add_user", &[("id", "42")]).is_some());
+    }
+
+    #[test]
+    fn encode_reserved() {
+        assert_eq!(encode("a b/c"), "a%20b%2Fc");
+        assert_eq!(encode("Az09-._~"), "Az09-._~");
+    }
+
+    #[test]
+    fn stats() {
+        let uut = Templates::default();
+        uut.register("ping", surf::get("https://example.com/ping").build())
+            .unwrap();
+        let (_, counters) = uut.request("ping", &[]).unwrap();
+        counters.record(false);
+        counters.record(true);
+        assert_eq!(
+            uut.stats("ping"),
+            Some(TemplateStats {
+                executions: 2,
+                failures: 1
+            })
+        );
+        assert_eq!(uut.all_stats().len(), 1);
+    }
+}
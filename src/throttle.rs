@@ -0,0 +1,283 @@
+//! Aggregate bandwidth throttling
+use crate::stats::SlotState;
+use crate::ErrorKind;
+use async_std::io;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use surf::Body;
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+/// A token bucket, where every token is a byte
+/// The bucket can hold up to one second worth of tokens; a consumer can go
+/// in debt, waiting the time needed to pay it back
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: u64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    /// Create a new bucket, refilled at `rate` bytes per second
+    /// The rate cannot be 0
+    pub(crate) fn new(rate: u64) -> Self {
+        assert!(rate > 0);
+        TokenBucket {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take the tokens for `bytes`, returning the time to wait to pay the
+    /// debt back
+    fn take(&self, bytes: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.last).as_secs_f64() * self.rate as f64;
+        state.tokens = (state.tokens + refill).min(self.rate as f64);
+        state.last = now;
+        state.tokens -= bytes as f64;
+        if state.tokens < 0.0 {
+            Duration::from_secs_f64(-state.tokens / self.rate as f64)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+type Delay = Pin<Box<dyn Future<Output = ()> + Send + Sync>>;
+
+/// A request body, that counts the bytes read by the client while sending
+/// it; if a bucket is available, every chunk is delivered only after its
+/// tokens are paid
+pub(crate) struct MeteredReader {
+    inner: Body,
+    bucket: Option<Arc<TokenBucket>>,
+    read: Arc<AtomicU64>,
+    chunk: Vec<u8>,
+    pos: usize,
+    delay: Option<Delay>,
+}
+
+impl MeteredReader {
+    pub(crate) fn new(inner: Body, bucket: Option<Arc<TokenBucket>>, read: Arc<AtomicU64>) -> Self {
+        MeteredReader {
+            inner,
+            bucket,
+            read,
+            chunk: Vec::new(),
+            pos: 0,
+            delay: None,
+        }
+    }
+}
+
+impl io::Read for MeteredReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            if let Some(delay) = this.delay.as_mut() {
+                if delay.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.delay = None;
+            }
+            if this.pos < this.chunk.len() {
+                let n = buf.len().min(this.chunk.len() - this.pos);
+                buf[..n].copy_from_slice(&this.chunk[this.pos..this.pos + n]);
+                this.pos += n;
+                this.read.fetch_add(n as u64, Ordering::Relaxed);
+                return Poll::Ready(Ok(n));
+            }
+            this.chunk.resize(CHUNK_SIZE, 0);
+            this.pos = 0;
+            let n = match Pin::new(&mut this.inner).poll_read(cx, &mut this.chunk) {
+                Poll::Ready(Ok(n)) => n,
+                other => {
+                    this.chunk.clear();
+                    return other;
+                }
+            };
+            this.chunk.truncate(n);
+            if n == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            if let Some(bucket) = &this.bucket {
+                let wait = bucket.take(n as u64);
+                if wait > Duration::from_secs(0) {
+                    this.delay = Some(Box::pin(async_std::task::sleep(wait)));
+                }
+            }
+        }
+    }
+}
+
+/// A response body, metered and throttled as a [`MeteredReader`]
+/// The guard holds the resources used by the request until the body is fully
+/// received, it fails or it's dropped; then the bytes received, and the
+/// error if any, are recorded in the state of the slot
+pub(crate) struct ResponseReader<G> {
+    inner: MeteredReader,
+    received: Arc<AtomicU64>,
+    state: Arc<SlotState>,
+    guard: Option<G>,
+}
+
+impl<G> ResponseReader<G> {
+    pub(crate) fn new(
+        inner: Body,
+        bucket: Option<Arc<TokenBucket>>,
+        state: Arc<SlotState>,
+        guard: G,
+    ) -> Self {
+        let received = Arc::new(AtomicU64::new(0));
+        ResponseReader {
+            inner: MeteredReader::new(inner, bucket, received.clone()),
+            received,
+            state,
+            guard: Some(guard),
+        }
+    }
+
+    fn finish(&mut self, error: Option<&io::Error>) {
+        let guard = match self.guard.take() {
+            Some(guard) => guard,
+            None => return,
+        };
+        self.state
+            .add_bytes_received(self.received.load(Ordering::Relaxed));
+        if let Some(e) = error {
+            self.state.record_error(ErrorKind::Body, e);
+        }
+        drop(guard);
+    }
+}
+
+impl<G: Unpin> io::Read for ResponseReader<G> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        match &result {
+            Poll::Ready(Ok(0)) => self.finish(None),
+            Poll::Ready(Err(e)) => self.finish(Some(e)),
+            _ => {}
+        }
+        result
+    }
+}
+
+impl<G> Drop for ResponseReader<G> {
+    fn drop(&mut self) {
+        self.finish(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::ReadExt;
+
+    #[test]
+    fn burst_and_debt() {
+        let uut = TokenBucket::new(1000);
+        assert_eq!(uut.take(1000), Duration::from_secs(0));
+        assert!(uut.take(100) >= Duration::from_millis(90));
+    }
+
+    /// A reader that returns some bytes, then fails
+    struct Broken(usize);
+
+    impl io::Read for Broken {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.0 == 0 {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            let n = buf.len().min(self.0);
+            self.0 -= n;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    #[async_std::test]
+    async fn response_partial_body() {
+        let body = Body::from_reader(io::BufReader::new(Broken(10)), None);
+        let state = Arc::new(SlotState::default());
+        let guard = Arc::new(());
+        let mut uut = ResponseReader::new(body, None, state.clone(), guard.clone());
+        let mut bytes = Vec::new();
+        assert!(uut.read_to_end(&mut bytes).await.is_err());
+        // The guard is released as soon as the body fails
+        assert_eq!(Arc::strong_count(&guard), 1);
+        let stats = state.stats(0);
+        assert_eq!(stats.bytes_received, 10);
+        assert_eq!(stats.last_error.unwrap().kind, ErrorKind::Body);
+    }
+
+    #[async_std::test]
+    async fn response_dropped() {
+        let state = Arc::new(SlotState::default());
+        let guard = Arc::new(());
+        let mut uut = ResponseReader::new(
+            Body::from_bytes(vec![1u8; 100]),
+            None,
+            state.clone(),
+            guard.clone(),
+        );
+        let mut bytes = [0u8; 10];
+        uut.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(Arc::strong_count(&guard), 2);
+        drop(uut);
+        assert_eq!(Arc::strong_count(&guard), 1);
+        let stats = state.stats(0);
+        assert_eq!(stats.bytes_received, 10);
+        assert_eq!(stats.errors, 0);
+    }
+
+    #[async_std::test]
+    async fn metered_reader() {
+        let data = vec![42u8; 1200];
+        let read = Arc::new(AtomicU64::new(0));
+        let bucket = Arc::new(TokenBucket::new(1000));
+        let mut uut =
+            MeteredReader::new(Body::from_bytes(data.clone()), Some(bucket), read.clone());
+        let start = Instant::now();
+        let mut bytes = Vec::new();
+        uut.read_to_end(&mut bytes).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(bytes, data);
+        assert_eq!(read.load(Ordering::Relaxed), 1200);
+    }
+
+    #[async_std::test]
+    async fn metered_reader_without_bucket() {
+        let read = Arc::new(AtomicU64::new(0));
+        let mut uut = MeteredReader::new(Body::from_bytes(vec![1u8; 100]), None, read.clone());
+        let mut bytes = Vec::new();
+        uut.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(read.load(Ordering::Relaxed), 100);
+    }
+}